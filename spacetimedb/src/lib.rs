@@ -1,9 +1,17 @@
-use spacetimedb::{reducer, table, ReducerContext, SpacetimeType, Table};
+use spacetimedb::{reducer, table, ReducerContext, ScheduleAt, SpacetimeType, Table, TimeDuration, Timestamp};
+use std::collections::{HashMap, HashSet};
 
 fn now_iso(ctx: &ReducerContext) -> String {
     ctx.timestamp.to_rfc3339().unwrap_or_else(|_| "".to_string())
 }
 
+fn age_seconds(ctx: &ReducerContext, iso: &str) -> Option<u64> {
+    let ts: Timestamp = iso.parse().ok()?;
+    let now_micros = ctx.timestamp.to_micros_since_unix_epoch();
+    let then_micros = ts.to_micros_since_unix_epoch();
+    Some((now_micros.saturating_sub(then_micros).max(0) / 1_000_000) as u64)
+}
+
 #[table(accessor = template, public)]
 pub struct Template {
     #[primary_key]
@@ -15,6 +23,21 @@ pub struct Template {
     pub is_active: bool,
     pub created_at: String,
     pub updated_at: String,
+    #[default(0)]
+    pub version: u32,
+}
+
+#[table(accessor = template_version, public)]
+pub struct TemplateVersion {
+    #[primary_key]
+    pub id: String,
+    pub template_id: String,
+    pub version: u32,
+    pub name: String,
+    pub description: String,
+    pub schema_json: String,
+    pub extraction_rules: String,
+    pub created_at: String,
 }
 
 #[table(accessor = run, public)]
@@ -38,6 +61,8 @@ pub struct Run {
     pub provider: Option<String>,
     #[default(None::<String>)]
     pub document_key: Option<String>,
+    #[default(0)]
+    pub template_version: u32,
 }
 
 #[table(accessor = run_payload, public)]
@@ -51,6 +76,118 @@ pub struct RunPayload {
     pub raw_provider_json: String,
 }
 
+#[table(accessor = retention_policy, public)]
+pub struct RetentionPolicy {
+    #[primary_key]
+    pub id: String,
+    pub applies_to_mode: String,
+    pub status_filter: String,
+    pub max_age_seconds: u64,
+    pub enabled: bool,
+    pub dry_run: bool,
+}
+
+#[table(accessor = retention_audit, public)]
+pub struct RetentionAudit {
+    #[primary_key]
+    pub id: String,
+    pub policy_id: String,
+    pub run_id: String,
+    pub observed_at: String,
+}
+
+#[table(accessor = run_retention_sweep_schedule, scheduled = run_retention_sweep)]
+pub struct RunRetentionSweepSchedule {
+    #[primary_key]
+    #[auto_inc]
+    pub scheduled_id: u64,
+    pub scheduled_at: ScheduleAt,
+}
+
+#[table(accessor = run_embedding, public)]
+pub struct RunEmbedding {
+    #[primary_key]
+    pub id: String,
+    pub run_id: String,
+    pub chunk_index: u32,
+    pub text: String,
+    pub span_start: u32,
+    pub span_end: u32,
+    pub embedding: Vec<f32>,
+    pub norm: f32,
+}
+
+#[table(accessor = run_search_result, public)]
+pub struct RunSearchResult {
+    #[primary_key]
+    pub id: String,
+    pub search_id: String,
+    pub run_id: String,
+    pub chunk_index: u32,
+    pub score: f32,
+    pub rank: u32,
+}
+
+#[table(accessor = document_ref, public)]
+pub struct DocumentRef {
+    #[primary_key]
+    pub document_key: String,
+    pub provider: String,
+    pub bucket: String,
+    pub object_path: String,
+    pub byte_size: u64,
+    pub checksum: String,
+    pub content_type: String,
+    pub created_at: String,
+}
+
+#[table(accessor = access_grant, public)]
+pub struct AccessGrant {
+    #[primary_key]
+    #[auto_inc]
+    pub id: u64,
+    pub document_key: String,
+    pub requested_at: String,
+    pub expires_at: String,
+}
+
+#[table(accessor = run_stage, public)]
+pub struct RunStage {
+    #[primary_key]
+    pub id: String,
+    pub run_id: String,
+    pub stage_name: String,
+    pub started_at: String,
+    pub completed_at: String,
+    pub duration_ms: u64,
+    pub status: String,
+}
+
+#[table(accessor = run_metric, public)]
+pub struct RunMetric {
+    #[primary_key]
+    pub id: String,
+    pub run_id: String,
+    pub metric_key: String,
+    pub metric_value: f64,
+}
+
+#[table(accessor = stats_rollup, public)]
+pub struct StatsRollup {
+    #[primary_key]
+    pub id: String,
+    pub mode: String,
+    pub template_id: String,
+    pub since: String,
+    pub stage_name: String,
+    pub sample_count: u32,
+    pub success_rate: f64,
+    pub mean_duration_ms: f64,
+    pub p50_duration_ms: f64,
+    pub p95_duration_ms: f64,
+    pub computed_at: String,
+}
+
 #[derive(SpacetimeType, Clone)]
 pub struct TemplateUpsertArgs {
     pub id: String,
@@ -68,11 +205,18 @@ pub struct TemplateDeactivateArgs {
     pub id: String,
 }
 
+#[derive(SpacetimeType, Clone)]
+pub struct TemplateRestoreArgs {
+    pub id: String,
+    pub version: u32,
+}
+
 #[derive(SpacetimeType, Clone)]
 pub struct RunCreateArgs {
     pub id: String,
     pub mode: String,
     pub template_id: String,
+    pub template_version: u32,
     pub status: String,
     pub provider: String,
     pub document_key: Option<String>,
@@ -120,8 +264,118 @@ pub struct RunDeleteArgs {
     pub id: String,
 }
 
+#[derive(SpacetimeType, Clone)]
+pub enum RunBatchOp {
+    MarkProcessing(RunMarkProcessingArgs),
+    StorePayload(RunStorePayloadArgs),
+    MarkCompleted(RunMarkCompletedArgs),
+    MarkFailed(RunMarkFailedArgs),
+    Delete(RunDeleteArgs),
+}
+
+impl RunBatchOp {
+    fn run_id(&self) -> &str {
+        match self {
+            RunBatchOp::MarkProcessing(args) => &args.id,
+            RunBatchOp::StorePayload(args) => &args.id,
+            RunBatchOp::MarkCompleted(args) => &args.id,
+            RunBatchOp::MarkFailed(args) => &args.id,
+            RunBatchOp::Delete(args) => &args.id,
+        }
+    }
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct EmbeddingChunk {
+    pub chunk_index: u32,
+    pub text: String,
+    pub span_start: u32,
+    pub span_end: u32,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct RunStoreEmbeddingsArgs {
+    pub run_id: String,
+    pub chunks: Vec<EmbeddingChunk>,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct RunSearchArgs {
+    pub search_id: String,
+    pub query_vector: Vec<f32>,
+    pub top_k: u32,
+    pub min_score: f32,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct DocumentRegisterArgs {
+    pub document_key: String,
+    pub provider: String,
+    pub bucket: String,
+    pub object_path: String,
+    pub byte_size: u64,
+    pub checksum: String,
+    pub content_type: String,
+    pub created_at: String,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct DocumentUnregisterArgs {
+    pub document_key: String,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct DocumentRequestAccessArgs {
+    pub document_key: String,
+    pub ttl_seconds: u64,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct RunRecordStageArgs {
+    pub run_id: String,
+    pub stage_name: String,
+    pub started_at: String,
+    pub completed_at: String,
+    pub duration_ms: u64,
+    pub status: String,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct RunRecordMetricArgs {
+    pub run_id: String,
+    pub metric_key: String,
+    pub metric_value: f64,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct RunStatsRollupArgs {
+    pub mode: String,
+    pub template_id: String,
+    pub since: String,
+}
+
+#[derive(SpacetimeType, Clone)]
+pub struct RetentionPolicyUpsertArgs {
+    pub id: String,
+    pub applies_to_mode: String,
+    pub status_filter: String,
+    pub max_age_seconds: u64,
+    pub enabled: bool,
+    pub dry_run: bool,
+}
+
+const RETENTION_SWEEP_INTERVAL_SECONDS: i64 = 3600;
+
 #[reducer(init)]
-pub fn init(_ctx: &ReducerContext) {}
+pub fn init(ctx: &ReducerContext) {
+    ctx.db.run_retention_sweep_schedule().insert(RunRetentionSweepSchedule {
+        scheduled_id: 0,
+        scheduled_at: ScheduleAt::Interval(TimeDuration::from_micros(
+            RETENTION_SWEEP_INTERVAL_SECONDS * 1_000_000,
+        )),
+    });
+}
 
 #[reducer(client_connected)]
 pub fn on_connect(_ctx: &ReducerContext) {}
@@ -129,6 +383,26 @@ pub fn on_connect(_ctx: &ReducerContext) {}
 #[reducer(client_disconnected)]
 pub fn on_disconnect(_ctx: &ReducerContext) {}
 
+fn template_content_changed(existing: &Template, input: &TemplateUpsertArgs) -> bool {
+    existing.name != input.name
+        || existing.description != input.description
+        || existing.schema_json != input.schema_json
+        || existing.extraction_rules != input.extraction_rules
+}
+
+fn template_snapshot(ctx: &ReducerContext, row: &Template) {
+    ctx.db.template_version().insert(TemplateVersion {
+        id: format!("{}:{}", row.id, row.version),
+        template_id: row.id.clone(),
+        version: row.version,
+        name: row.name.clone(),
+        description: row.description.clone(),
+        schema_json: row.schema_json.clone(),
+        extraction_rules: row.extraction_rules.clone(),
+        created_at: row.created_at.clone(),
+    });
+}
+
 #[reducer]
 pub fn template_upsert(ctx: &ReducerContext, input: TemplateUpsertArgs) {
     let now = now_iso(ctx);
@@ -147,6 +421,16 @@ pub fn template_upsert(ctx: &ReducerContext, input: TemplateUpsertArgs) {
         now.clone()
     };
 
+    let should_snapshot = match &existing {
+        Some(row) => template_content_changed(row, &input),
+        None => true,
+    };
+    let version = match &existing {
+        Some(row) if !should_snapshot => row.version,
+        Some(row) => row.version + 1,
+        None => 1,
+    };
+
     let row = Template {
         id: input.id,
         name: input.name,
@@ -156,8 +440,13 @@ pub fn template_upsert(ctx: &ReducerContext, input: TemplateUpsertArgs) {
         is_active: input.is_active,
         created_at,
         updated_at,
+        version,
     };
 
+    if should_snapshot {
+        template_snapshot(ctx, &row);
+    }
+
     if existing.is_some() {
         ctx.db.template().id().update(row);
     } else {
@@ -176,6 +465,32 @@ pub fn template_deactivate(ctx: &ReducerContext, input: TemplateDeactivateArgs)
     }
 }
 
+#[reducer]
+pub fn template_restore(ctx: &ReducerContext, input: TemplateRestoreArgs) {
+    let snapshot_id = format!("{}:{}", input.id, input.version);
+    if let Some(snapshot) = ctx.db.template_version().id().find(&snapshot_id) {
+        template_upsert(
+            ctx,
+            TemplateUpsertArgs {
+                id: snapshot.template_id,
+                name: snapshot.name,
+                description: snapshot.description,
+                schema_json: snapshot.schema_json,
+                extraction_rules: snapshot.extraction_rules,
+                is_active: ctx
+                    .db
+                    .template()
+                    .id()
+                    .find(&input.id)
+                    .map(|row| row.is_active)
+                    .unwrap_or(true),
+                created_at: "".to_string(),
+                updated_at: "".to_string(),
+            },
+        );
+    }
+}
+
 #[reducer]
 pub fn run_create(ctx: &ReducerContext, input: RunCreateArgs) {
     let now = now_iso(ctx);
@@ -203,6 +518,7 @@ pub fn run_create(ctx: &ReducerContext, input: RunCreateArgs) {
         completed_at: "".to_string(),
         provider: Some(input.provider),
         document_key: input.document_key,
+        template_version: input.template_version,
     };
 
     let existing = ctx.db.run().id().find(&row.id);
@@ -278,6 +594,342 @@ pub fn run_mark_failed(ctx: &ReducerContext, input: RunMarkFailedArgs) {
 
 #[reducer]
 pub fn run_delete(ctx: &ReducerContext, input: RunDeleteArgs) {
-    ctx.db.run_payload().run_id().delete(&input.id);
-    ctx.db.run().id().delete(&input.id);
+    delete_run(ctx, &input.id);
+}
+
+fn delete_run(ctx: &ReducerContext, id: &str) {
+    let document_key = ctx
+        .db
+        .run()
+        .id()
+        .find(id)
+        .and_then(|row| row.document_key.clone());
+
+    ctx.db.run_payload().run_id().delete(id);
+    ctx.db.run().id().delete(id);
+
+    if let Some(key) = document_key {
+        let still_referenced = ctx
+            .db
+            .run()
+            .iter()
+            .any(|row| row.document_key.as_deref() == Some(key.as_str()));
+        if !still_referenced {
+            unregister_document(ctx, &key);
+        }
+    }
+}
+
+fn unregister_document(ctx: &ReducerContext, document_key: &str) {
+    ctx.db.document_ref().document_key().delete(document_key);
+}
+
+#[reducer]
+pub fn run_batch_apply(ctx: &ReducerContext, ops: Vec<RunBatchOp>) {
+    for op in ops {
+        if ctx.db.run().id().find(op.run_id()).is_none() {
+            continue;
+        }
+        match op {
+            RunBatchOp::MarkProcessing(args) => run_mark_processing(ctx, args),
+            RunBatchOp::StorePayload(args) => run_store_payload(ctx, args),
+            RunBatchOp::MarkCompleted(args) => run_mark_completed(ctx, args),
+            RunBatchOp::MarkFailed(args) => run_mark_failed(ctx, args),
+            RunBatchOp::Delete(args) => delete_run(ctx, &args.id),
+        }
+    }
+}
+
+#[reducer]
+pub fn retention_policy_upsert(ctx: &ReducerContext, input: RetentionPolicyUpsertArgs) {
+    let row = RetentionPolicy {
+        id: input.id,
+        applies_to_mode: input.applies_to_mode,
+        status_filter: input.status_filter,
+        max_age_seconds: input.max_age_seconds,
+        enabled: input.enabled,
+        dry_run: input.dry_run,
+    };
+
+    if ctx.db.retention_policy().id().find(&row.id).is_some() {
+        ctx.db.retention_policy().id().update(row);
+    } else {
+        ctx.db.retention_policy().insert(row);
+    }
+}
+
+#[reducer]
+pub fn run_retention_sweep(ctx: &ReducerContext, _schedule: RunRetentionSweepSchedule) {
+    if ctx.sender != ctx.identity() {
+        return;
+    }
+
+    let policies: Vec<RetentionPolicy> = ctx
+        .db
+        .retention_policy()
+        .iter()
+        .filter(|policy| policy.enabled)
+        .collect();
+
+    for policy in policies {
+        let candidates: Vec<Run> = ctx
+            .db
+            .run()
+            .iter()
+            .filter(|run| run.mode == policy.applies_to_mode)
+            .filter(|run| policy.status_filter.is_empty() || run.status == policy.status_filter)
+            .filter(|run| {
+                let anchor = if !run.completed_at.is_empty() {
+                    &run.completed_at
+                } else {
+                    &run.created_at
+                };
+                age_seconds(ctx, anchor)
+                    .map(|age| age >= policy.max_age_seconds)
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        for run in candidates {
+            if policy.dry_run {
+                let audit_row = RetentionAudit {
+                    id: format!("{}:{}", policy.id, run.id),
+                    policy_id: policy.id.clone(),
+                    run_id: run.id,
+                    observed_at: now_iso(ctx),
+                };
+
+                if ctx.db.retention_audit().id().find(&audit_row.id).is_some() {
+                    ctx.db.retention_audit().id().update(audit_row);
+                } else {
+                    ctx.db.retention_audit().insert(audit_row);
+                }
+            } else {
+                delete_run(ctx, &run.id);
+            }
+        }
+    }
+}
+
+fn l2_norm(v: &[f32]) -> f32 {
+    v.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+#[reducer]
+pub fn run_store_embeddings(ctx: &ReducerContext, input: RunStoreEmbeddingsArgs) {
+    for chunk in input.chunks {
+        let norm = l2_norm(&chunk.embedding);
+        if norm == 0.0 {
+            continue;
+        }
+        let normalized: Vec<f32> = chunk.embedding.iter().map(|x| x / norm).collect();
+
+        let row = RunEmbedding {
+            id: format!("{}:{}", input.run_id, chunk.chunk_index),
+            run_id: input.run_id.clone(),
+            chunk_index: chunk.chunk_index,
+            text: chunk.text,
+            span_start: chunk.span_start,
+            span_end: chunk.span_end,
+            norm,
+            embedding: normalized,
+        };
+
+        if ctx.db.run_embedding().id().find(&row.id).is_some() {
+            ctx.db.run_embedding().id().update(row);
+        } else {
+            ctx.db.run_embedding().insert(row);
+        }
+    }
+}
+
+#[reducer]
+pub fn run_search(ctx: &ReducerContext, input: RunSearchArgs) {
+    let stale: Vec<String> = ctx
+        .db
+        .run_search_result()
+        .iter()
+        .filter(|row| row.search_id == input.search_id)
+        .map(|row| row.id)
+        .collect();
+    for id in stale {
+        ctx.db.run_search_result().id().delete(&id);
+    }
+
+    let query_norm = l2_norm(&input.query_vector);
+    if query_norm == 0.0 {
+        return;
+    }
+    let query_unit: Vec<f32> = input.query_vector.iter().map(|x| x / query_norm).collect();
+
+    let mut scored: Vec<(String, u32, f32)> = ctx
+        .db
+        .run_embedding()
+        .iter()
+        .filter(|row| row.norm > 0.0 && row.embedding.len() == query_unit.len())
+        .map(|row| {
+            let score: f32 = row.embedding.iter().zip(query_unit.iter()).map(|(a, b)| a * b).sum();
+            (row.run_id, row.chunk_index, score)
+        })
+        .filter(|(_, _, score)| *score >= input.min_score)
+        .collect();
+
+    scored.sort_by(|a, b| b.2.total_cmp(&a.2));
+    scored.truncate(input.top_k as usize);
+
+    for (rank, (run_id, chunk_index, score)) in scored.into_iter().enumerate() {
+        ctx.db.run_search_result().insert(RunSearchResult {
+            id: format!("{}:{}", input.search_id, rank),
+            search_id: input.search_id.clone(),
+            run_id,
+            chunk_index,
+            score,
+            rank: rank as u32,
+        });
+    }
+}
+
+#[reducer]
+pub fn document_register(ctx: &ReducerContext, input: DocumentRegisterArgs) {
+    let now = now_iso(ctx);
+    let created_at = if !input.created_at.is_empty() {
+        input.created_at
+    } else {
+        now
+    };
+
+    let row = DocumentRef {
+        document_key: input.document_key,
+        provider: input.provider,
+        bucket: input.bucket,
+        object_path: input.object_path,
+        byte_size: input.byte_size,
+        checksum: input.checksum,
+        content_type: input.content_type,
+        created_at,
+    };
+
+    if ctx.db.document_ref().document_key().find(&row.document_key).is_some() {
+        ctx.db.document_ref().document_key().update(row);
+    } else {
+        ctx.db.document_ref().insert(row);
+    }
+}
+
+#[reducer]
+pub fn document_unregister(ctx: &ReducerContext, input: DocumentUnregisterArgs) {
+    unregister_document(ctx, &input.document_key);
+}
+
+#[reducer]
+pub fn document_request_access(ctx: &ReducerContext, input: DocumentRequestAccessArgs) {
+    let expires_at = ctx.timestamp + TimeDuration::from_micros((input.ttl_seconds as i64) * 1_000_000);
+
+    ctx.db.access_grant().insert(AccessGrant {
+        id: 0,
+        document_key: input.document_key,
+        requested_at: now_iso(ctx),
+        expires_at: expires_at.to_rfc3339().unwrap_or_else(|_| "".to_string()),
+    });
+}
+
+#[reducer]
+pub fn run_record_stage(ctx: &ReducerContext, input: RunRecordStageArgs) {
+    let row = RunStage {
+        id: format!("{}:{}", input.run_id, input.stage_name),
+        run_id: input.run_id,
+        stage_name: input.stage_name,
+        started_at: input.started_at,
+        completed_at: input.completed_at,
+        duration_ms: input.duration_ms,
+        status: input.status,
+    };
+
+    if ctx.db.run_stage().id().find(&row.id).is_some() {
+        ctx.db.run_stage().id().update(row);
+    } else {
+        ctx.db.run_stage().insert(row);
+    }
+}
+
+#[reducer]
+pub fn run_record_metric(ctx: &ReducerContext, input: RunRecordMetricArgs) {
+    let row = RunMetric {
+        id: format!("{}:{}", input.run_id, input.metric_key),
+        run_id: input.run_id,
+        metric_key: input.metric_key,
+        metric_value: input.metric_value,
+    };
+
+    if ctx.db.run_metric().id().find(&row.id).is_some() {
+        ctx.db.run_metric().id().update(row);
+    } else {
+        ctx.db.run_metric().insert(row);
+    }
+}
+
+fn percentile(sorted_durations: &[u64], p: f64) -> f64 {
+    if sorted_durations.is_empty() {
+        return 0.0;
+    }
+    let rank = ((sorted_durations.len() - 1) as f64 * p).round() as usize;
+    sorted_durations[rank] as f64
+}
+
+#[reducer]
+pub fn run_stats_rollup(ctx: &ReducerContext, input: RunStatsRollupArgs) {
+    let matching_runs: Vec<Run> = ctx
+        .db
+        .run()
+        .iter()
+        .filter(|run| run.mode == input.mode)
+        .filter(|run| run.template_id == input.template_id)
+        .filter(|run| run.created_at.as_str() >= input.since.as_str())
+        .collect();
+
+    let terminal_runs: Vec<&Run> = matching_runs
+        .iter()
+        .filter(|run| run.status == "completed" || run.status == "failed")
+        .collect();
+    let terminal_count = terminal_runs.len() as u32;
+    let success_rate = if terminal_count == 0 {
+        0.0
+    } else {
+        let completed = terminal_runs.iter().filter(|run| run.status == "completed").count();
+        completed as f64 / terminal_count as f64
+    };
+
+    let run_ids: HashSet<&str> = matching_runs.iter().map(|run| run.id.as_str()).collect();
+
+    let mut durations_by_stage: HashMap<String, Vec<u64>> = HashMap::new();
+    for stage in ctx.db.run_stage().iter() {
+        if run_ids.contains(stage.run_id.as_str()) {
+            durations_by_stage.entry(stage.stage_name.clone()).or_default().push(stage.duration_ms);
+        }
+    }
+
+    for (stage_name, mut durations) in durations_by_stage {
+        durations.sort_unstable();
+        let mean_duration_ms = durations.iter().sum::<u64>() as f64 / durations.len() as f64;
+
+        let row = StatsRollup {
+            id: format!("{}:{}:{}:{}", input.mode, input.template_id, input.since, stage_name),
+            mode: input.mode.clone(),
+            template_id: input.template_id.clone(),
+            since: input.since.clone(),
+            stage_name,
+            sample_count: terminal_count,
+            success_rate,
+            mean_duration_ms,
+            p50_duration_ms: percentile(&durations, 0.5),
+            p95_duration_ms: percentile(&durations, 0.95),
+            computed_at: now_iso(ctx),
+        };
+
+        if ctx.db.stats_rollup().id().find(&row.id).is_some() {
+            ctx.db.stats_rollup().id().update(row);
+        } else {
+            ctx.db.stats_rollup().insert(row);
+        }
+    }
 }